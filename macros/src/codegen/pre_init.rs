@@ -5,30 +5,72 @@ use rtic_syntax::ast::App;
 use crate::{analyze::Analysis, check::Extra, codegen::util};
 
 /// Generates code that runs before `#[init]`
+///
+/// `core` is the core this `init` runs on; in a multi-core (`cores > 1`) app `codegen` is invoked
+/// once per core, and every loop below is filtered down to the statics, interrupts and tasks that
+/// `core` actually owns -- each core's `init` only ever touches its own peripherals and barriers
+///
+/// This module only owns the statements, not the types they reference: `extra.{monotonic,cores,
+/// nvic_prio_bits}`, the `rtic::Monotonic` trait itself, `analysis.timer_queues[_].{core,binds}`
+/// (which core owns a given `Monotonic` and the interrupt/exception its timer fires on), and the
+/// `core` field expected on every hardware/software task and on each entry of `analysis.interrupts`
+/// are all expected to be defined by the analysis pass and the `rtic` crate, neither of which lives
+/// under `macros/src/codegen/`
 pub fn codegen(
+    core: u8,
     app: &App,
     analysis: &Analysis,
     extra: &Extra,
-) ->
+) -> (
     // `pre_init_stmts`
-    Vec<TokenStream2>
-{
+    Vec<TokenStream2>,
+    // `pre_init_const_app_items` -- `static` items (e.g. barriers) that must live at module scope
+    // rather than inside the `init` function body
+    Vec<TokenStream2>,
+) {
     let mut stmts = vec![];
+    let mut const_app = vec![];
 
     // disable interrupts -- `init` must run with interrupts disabled
     stmts.push(quote!(rtic::export::interrupt::disable();));
 
-    // populate the FreeQueue
-    for fq in &analysis.free_queues {
-        // Get the task name
-        let name = fq.0;
+    // populate the FreeQueues owned by this core -- one per (task, sender core) pair
+    //
+    // `payload = pool` tasks have no free queue: their messages are `Box`es handed out by a
+    // `heapless::pool::Pool`, grown below instead of pre-filled here
+    for (name, senders) in &analysis.free_queues {
+        if analysis.pool_tasks.contains(name) {
+            continue;
+        }
+
         let task = &app.software_tasks[name];
+        if task.args.core != core {
+            continue;
+        }
+
         let cap = task.args.capacity;
 
-        let fq_ident = util::fq_ident(name);
+        for &sender in senders {
+            let fq_ident = util::fq_ident(name, sender);
+
+            stmts.push(quote!(
+                (0..#cap).for_each(|i| #fq_ident.enqueue_unchecked(i));
+            ));
+        }
+    }
+
+    // grow this core's `payload = pool` tasks' pools with their static backing storage
+    for name in &analysis.pool_tasks {
+        let task = &app.software_tasks[name];
+        if task.args.core != core {
+            continue;
+        }
+
+        let pool = util::pool_ident(name);
+        let storage = util::pool_storage_ident(name);
 
         stmts.push(quote!(
-            (0..#cap).for_each(|i| #fq_ident.enqueue_unchecked(i));
+            #pool.grow_exact(&mut #storage);
         ));
     }
 
@@ -40,21 +82,38 @@ pub fn codegen(
     let device = extra.device;
     let nvic_prio_bits = quote!(#device::NVIC_PRIO_BITS);
 
-    // unmask interrupts and set their priorities
+    // this core's timer queue, if it has one -- at most one `Monotonic` per core
+    let tq = analysis.timer_queues.iter().find(|tq| tq.core == core);
+
+    // unmask this core's interrupts and set their priorities
     for (&priority, name) in analysis
         .interrupts
         .iter()
-        .chain(app.hardware_tasks.values().flat_map(|task| {
-            if !util::is_exception(&task.args.binds) {
+        .filter_map(|(priority, &(core_of, ref name))| {
+            if core_of == core {
+                Some((priority, name))
+            } else {
+                None
+            }
+        })
+        .chain(app.hardware_tasks.values().filter_map(|task| {
+            if task.args.core == core && !util::is_exception(&task.args.binds) {
                 Some((&task.args.priority, &task.args.binds))
             } else {
-                // we do exceptions in another pass
+                // not ours, or we do exceptions in another pass
                 None
             }
         }))
+        // the interrupt that drives the `Monotonic` timer backing the `TimerQueue`, if any -- a
+        // `Monotonic` that binds to a Cortex-M exception (e.g. the stock SysTick/DWT monotonic) is
+        // handled in the exception pass below instead, same as for hardware tasks
+        .chain(
+            tq.filter(|tq| !util::is_exception(&tq.binds))
+                .map(|tq| (&tq.priority, &tq.binds)),
+        )
     {
-        // compile time assert that this priority is supported by the device
-        stmts.push(quote!(let _ = [(); ((1 << #nvic_prio_bits) - #priority as usize)];));
+        // assert that this priority is supported by the device, with a spanned error when possible
+        stmts.push(util::priority_check(extra, &nvic_prio_bits, priority, name));
 
         // NOTE this also checks that the interrupt exists in the `Interrupt` enumeration
         let interrupt = util::interrupt_ident();
@@ -72,19 +131,12 @@ pub fn codegen(
 
     // cross-spawn barriers: now that priorities have been set and the interrupts have been unmasked
     // we are ready to receive messages from *other* cores
-    /*
     if analysis.spawn_barriers.contains_key(&core) {
         let sb = util::spawn_barrier(core);
-        let shared = if cfg!(feature = "heterogeneous") {
-            Some(quote!(
-                #[rtic::export::shared]
-            ))
-        } else {
-            None
-        };
 
+        // the barrier must be visible to every core that may `spawn` into this one
         const_app.push(quote!(
-            #shared
+            #[rtic::export::shared]
             static #sb: rtic::export::Barrier = rtic::export::Barrier::new();
         ));
 
@@ -93,18 +145,27 @@ pub fn codegen(
             #sb.release();
         ));
     }
-    */
-
-    // set exception priorities
-    for (name, priority) in app.hardware_tasks.values().filter_map(|task| {
-        if util::is_exception(&task.args.binds) {
-            Some((&task.args.binds, task.args.priority))
-        } else {
-            None
-        }
-    }) {
-        // compile time assert that this priority is supported by the device
-        stmts.push(quote!(let _ = [(); ((1 << #nvic_prio_bits) - #priority as usize)];));
+
+    // set this core's exception priorities
+    for (name, priority) in app
+        .hardware_tasks
+        .values()
+        .filter_map(|task| {
+            if task.args.core == core && util::is_exception(&task.args.binds) {
+                Some((&task.args.binds, task.args.priority))
+            } else {
+                None
+            }
+        })
+        // a `Monotonic` bound to an exception is set up here, alongside the other exceptions,
+        // instead of through the NVIC above (there's no `Interrupt::SysTick` variant)
+        .chain(
+            tq.filter(|tq| util::is_exception(&tq.binds))
+                .map(|tq| (&tq.binds, tq.priority)),
+        )
+    {
+        // assert that this priority is supported by the device, with a spanned error when possible
+        stmts.push(util::priority_check(extra, &nvic_prio_bits, priority, name));
 
         stmts.push(quote!(core.SCB.set_priority(
             rtic::export::SystemHandler::#name,
@@ -112,32 +173,31 @@ pub fn codegen(
         );));
     }
 
-    // initialize the SysTick if there exist a TimerQueue
-    if let Some(tq) = analysis.timer_queues.first() {
-        let priority = tq.priority;
-
-        // compile time assert that this priority is supported by the device
-        stmts.push(quote!(let _ = [(); ((1 << #nvic_prio_bits) - #priority as usize)];));
-
-        stmts.push(quote!(core.SCB.set_priority(
-            rtic::export::SystemHandler::SysTick,
-            rtic::export::logical2hw(#priority, #nvic_prio_bits),
-        );));
+    // bring up the `Monotonic` timer backing this core's `TimerQueue`, if there is one
+    //
+    // its interrupt or exception was already given a priority in one of the loops above (and, if it
+    // is an interrupt, unmasked), so all that's left is to zero the timer and let it run
+    if tq.is_some() {
+        let monotonic = &extra.monotonic;
 
         stmts.push(quote!(
-            core.SYST.set_clock_source(rtic::export::SystClkSource::Core);
-            core.SYST.enable_counter();
-            core.DCB.enable_trace();
+            <#monotonic as rtic::Monotonic>::reset();
+            <#monotonic as rtic::Monotonic>::enable_timer();
         ));
     }
 
+    // `#[app(sleep = ...)]` opted into SLEEPDEEP: the default idle (and any WFI/WFE in a user
+    // `#[idle]`) will drop into the device's stop/standby mode instead of plain sleep
+    if extra.sleep_deep {
+        stmts.push(quote!(core.SCB.scr.modify(|r| r | 1 << 2);));
+    }
+
     // if there's no user `#[idle]` then optimize returning from interrupt handlers
     if app.idles.is_empty() {
         // Set SLEEPONEXIT bit to enter sleep mode when returning from ISR
         stmts.push(quote!(core.SCB.scr.modify(|r| r | 1 << 1);));
     }
 
-    /*
     // cross-spawn barriers: wait until other cores are ready to receive messages
     for (&receiver, senders) in &analysis.spawn_barriers {
         // only block here if `init` can send messages to `receiver`
@@ -149,7 +209,6 @@ pub fn codegen(
             ));
         }
     }
-    */
 
-    stmts
+    (stmts, const_app)
 }
@@ -1,9 +1,9 @@
 use core::sync::atomic::{AtomicUsize, Ordering};
 
 use proc_macro2::{Span, TokenStream as TokenStream2};
-use quote::quote;
+use quote::{quote, quote_spanned};
 use rtic_syntax::{ast::App, Context};
-use syn::{Attribute, Ident, LitInt, PatType};
+use syn::{Attribute, Error, Ident, LitInt, PatType};
 
 use crate::check::Extra;
 
@@ -25,27 +25,23 @@ pub fn capacity_typenum(capacity: u8, round_up_to_power_of_two: bool) -> TokenSt
     quote!(rtic::export::consts::#ident)
 }
 
-/*
 /// Generates a `#[cfg(core = "0")]` attribute if we are in multi-core mode
-pub fn cfg_core(core: Core, cores: u8) -> Option<TokenStream2> {
+pub fn cfg_core(core: u8, cores: u8) -> Option<TokenStream2> {
     if cores == 1 {
         None
-    } else if cfg!(feature = "heterogeneous") {
+    } else {
         let core = core.to_string();
         Some(quote!(#[cfg(core = #core)]))
-    } else {
-        None
     }
 }
-*/
 
 /// Identifier for the free queue
 ///
 /// There may be more than one free queue per task because we need one for each sender core so we
 /// include the sender (e.g. `S0`) in the name
-pub fn fq_ident(task: &Ident) -> Ident {
+pub fn fq_ident(task: &Ident, sender: u8) -> Ident {
     Ident::new(
-        &format!("{}_FQ", task.to_string()),
+        &format!("{}_S{}_FQ", task.to_string(), sender),
         Span::call_site(),
     )
 }
@@ -93,19 +89,67 @@ pub fn impl_mutex(
     )
 }
 
-/*
 /// Generates an identifier for a cross-initialization barrier
-pub fn init_barrier(initializer: Core) -> Ident {
+pub fn init_barrier(initializer: u8) -> Ident {
     Ident::new(&format!("IB{}", initializer), Span::call_site())
 }
-*/
 
 /// Generates an identifier for the `INPUTS` buffer (`spawn` & `schedule` API)
+///
+/// Only used by tasks with the default `payload = inputs` mode; `payload = pool` tasks move their
+/// message by `Box<T>` instead, see [`pool_ident`] and [`pool_storage_ident`]
 pub fn inputs_ident(task: &Ident) -> Ident {
     Ident::new(&format!("{}_INPUTS", task), Span::call_site())
 }
 
+/// Generates an identifier for the `heapless::pool::Pool` backing a `#[task(payload = pool)]`
+pub fn pool_ident(task: &Ident) -> Ident {
+    Ident::new(&format!("{}_POOL", task), Span::call_site())
+}
+
+/// Generates an identifier for the static storage a pool-backed task's `Pool` is grown with
+pub fn pool_storage_ident(task: &Ident) -> Ident {
+    Ident::new(&format!("{}_POOL_STORAGE", task), Span::call_site())
+}
+
+/// Asserts that `priority` fits within the device's NVIC priority levels
+///
+/// When `extra.nvic_prio_bits` is statically known (the analysis pass was able to resolve the
+/// device's `NVIC_PRIO_BITS` to a literal) an out-of-range priority is rejected immediately with a
+/// spanned error pointing at the offending interrupt/exception name. Otherwise `NVIC_PRIO_BITS` can
+/// only be resolved by rustc's const evaluator, so this falls back to the classic
+/// array-length-trick assert -- it still rejects bad priorities, just with the opaque "attempt to
+/// subtract with overflow" error instead of a pointer to the user's source
+pub fn priority_check(
+    extra: &Extra,
+    nvic_prio_bits: &TokenStream2,
+    priority: u8,
+    name: &Ident,
+) -> TokenStream2 {
+    if let Some(bits) = extra.nvic_prio_bits {
+        let levels = 1u32 << bits;
+
+        return if u32::from(priority) > levels {
+            Error::new(
+                name.span(),
+                format!(
+                    "task priority {} exceeds the {} priority level(s) supported by this device (NVIC_PRIO_BITS = {})",
+                    priority, levels, bits,
+                ),
+            )
+            .to_compile_error()
+        } else {
+            quote!()
+        };
+    }
+
+    quote_spanned!(name.span()=> let _ = [(); ((1 << #nvic_prio_bits) - #priority as usize)];)
+}
+
 /// Generates an identifier for the `INSTANTS` buffer (`schedule` API)
+///
+/// Holds `<Mono as Monotonic>::Instant`s, so it works with whichever timer the app's `monotonic`
+/// implements, not just the DWT cycle counter
 pub fn instants_ident(task: &Ident) -> Ident {
     Ident::new(&format!("{}_INSTANTS", task), Span::call_site())
 }
@@ -178,12 +222,10 @@ pub fn locals_ident(ctxt: Context, app: &App) -> Ident {
     Ident::new(&s, Span::call_site())
 }
 
-/*
 /// Generates an identifier for a rendezvous barrier
 pub fn rendezvous_ident() -> Ident {
     Ident::new(&format!("RV"), Span::call_site())
 }
-*/
 
 // Regroups the inputs of a task
 //
@@ -252,9 +294,9 @@ pub fn resources_ident(ctxt: Context, app: &App) -> Ident {
 /// Each core may have several task dispatchers, one for each priority level. Each task dispatcher
 /// in turn may use more than one ready queue because the queues are SPSC queues so one is needed
 /// per sender core.
-pub fn rq_ident(priority: u8) -> Ident {
+pub fn rq_ident(priority: u8, sender: u8) -> Ident {
     Ident::new(
-        &format!("P{}_RQ", priority),
+        &format!("P{}_S{}_RQ", priority, sender),
         Span::call_site(),
     )
 }
@@ -275,12 +317,13 @@ pub fn schedule_t_ident() -> Ident {
     Ident::new(&format!("T"), Span::call_site())
 }
 
-/*
 /// Generates an identifier for a cross-spawn barrier
-pub fn spawn_barrier() -> Ident {
-    Ident::new(&format!("SB"), Span::call_site())
+///
+/// There's one barrier per receiving core: senders on other cores `wait()` on it before their
+/// first `spawn`, and the receiving core `release()`s it once it is ready to accept messages
+pub fn spawn_barrier(core: u8) -> Ident {
+    Ident::new(&format!("SB{}", core), Span::call_site())
 }
-*/
 
 /// Generates an identifier for a "spawn" function
 ///
@@ -311,7 +354,8 @@ pub fn suffixed(name: &str) -> Ident {
 
 /// Generates an identifier for a timer queue
 ///
-/// At most there's one timer queue per core
+/// At most there's one timer queue per core. The queue is driven by whatever hardware timer the
+/// app's `#[app(monotonic = ...)]` points at -- it's not tied to a particular peripheral
 pub fn tq_ident() -> Ident {
     Ident::new(&format!("TQ"), Span::call_site())
 }